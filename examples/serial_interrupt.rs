@@ -0,0 +1,125 @@
+//! Echoes received bulk OUT packets back on IN, entirely through `UsbBus::on_interrupt` and the
+//! `asynch` futures, instead of busy-polling `UsbBus::poll()` from the main loop as
+//! `examples/serial.rs` does. This exercises the path `on_interrupt` exists for: a received
+//! packet wakes `asynch::read` from the USB interrupt handler.
+//!
+//! Unlike `examples/serial.rs` this skips `usb_device::UsbDevice` and the CDC-ACM class
+//! entirely, since both are layered on top of the synchronous `poll()` API; this example only
+//! needs a single raw bulk endpoint to demonstrate the interrupt-driven path.
+#![no_std]
+#![no_main]
+
+extern crate cortex_m;
+#[macro_use]
+extern crate cortex_m_rt as rt;
+extern crate panic_semihosting;
+extern crate stm32f103xx_hal as hal;
+extern crate usb_device;
+extern crate stm32f103xx_usb;
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use hal::prelude::*;
+use hal::stm32f103xx;
+use hal::delay::Delay;
+use rt::ExceptionFrame;
+use usb_device::endpoint::EndpointType;
+use stm32f103xx_usb::{asynch, UsbBus};
+
+type Bus = UsbBus<stm32f103xx::USB, Delay>;
+
+// Set once in `main`, before the USB interrupt is unmasked, then only ever read (from `main` and
+// from the interrupt handler) as a `&'static` reference; `UsbBus`'s own fields provide whatever
+// further synchronization each of those accesses needs from there.
+static mut USB_BUS: Option<usb_device::bus::UsbBusWrapper<Bus>> = None;
+
+fn usb_bus() -> &'static usb_device::bus::UsbBusWrapper<Bus> {
+    unsafe { USB_BUS.as_ref().unwrap() }
+}
+
+// This example drives everything from one "interrupt, then re-poll" loop rather than a real
+// executor, so waking just needs to break the `wfi` below, not reschedule a task.
+unsafe fn noop(_: *const ()) {}
+unsafe fn noop_clone(_: *const ()) -> RawWaker { noop_raw_waker() }
+fn noop_raw_waker() -> RawWaker {
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(noop_clone, noop, noop, noop);
+    RawWaker::new(core::ptr::null(), &VTABLE)
+}
+
+fn block_on<F: Future>(mut fut: F) -> F::Output {
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = unsafe { Pin::new_unchecked(&mut fut) };
+
+    loop {
+        if let Poll::Ready(result) = fut.as_mut().poll(&mut cx) {
+            return result;
+        }
+
+        // Woken by the USB interrupt, which runs `on_interrupt` and wakes this endpoint's waker;
+        // `wfi` just parks the core until then.
+        cortex_m::asm::wfi();
+    }
+}
+
+entry!(main);
+fn main() -> ! {
+    let cp = cortex_m::Peripherals::take().unwrap();
+    let dp = stm32f103xx::Peripherals::take().unwrap();
+
+    let mut flash = dp.FLASH.constrain();
+    let mut rcc = dp.RCC.constrain();
+
+    let clocks = rcc.cfgr
+        .hse(8.mhz())
+        .sysclk(48.mhz())
+        .pclk1(24.mhz())
+        .freeze(&mut flash.acr);
+
+    assert!(clocks.usbclk_valid());
+
+    let mut gpioa = dp.GPIOA.split(&mut rcc.apb2);
+
+    let mut delay = Delay::new(cp.SYST, clocks);
+
+    // hack to simulate USB reset
+    {
+        let mut pa12 = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
+        pa12.set_low();
+        delay.delay_ms(10u8);
+    }
+
+    let usb_bus = UsbBus::usb(dp.USB, delay, &mut rcc.apb1);
+    let eps = usb_bus.endpoints().unwrap();
+    let (read_ep, write_ep) = eps.ep1.split(EndpointType::Bulk, 64);
+    let read_addr = read_ep.address();
+    let write_addr = write_ep.address();
+
+    unsafe {
+        USB_BUS = Some(usb_bus);
+        cortex_m::peripheral::NVIC::unmask(stm32f103xx::Interrupt::USB_LP_CAN_RX0);
+    }
+
+    loop {
+        let mut buf = [0u8; 64];
+        let count = block_on(asynch::read(usb_bus(), read_addr, &mut buf)).unwrap();
+        block_on(asynch::write(usb_bus(), write_addr, &buf[0..count])).unwrap();
+    }
+}
+
+interrupt!(USB_LP_CAN_RX0, usb_interrupt);
+fn usb_interrupt() {
+    usb_bus().on_interrupt();
+}
+
+exception!(HardFault, hard_fault);
+fn hard_fault(ef: &ExceptionFrame) -> ! {
+    panic!("{:#?}", ef);
+}
+
+exception!(*, default_handler);
+fn default_handler(irqn: i16) {
+    panic!("Unhandled exception (IRQn = {})", irqn);
+}
@@ -0,0 +1,133 @@
+//! Interrupt-driven async API layered on top of [`UsbBus`], modeled on embassy's USB driver:
+//! an [`AtomicWaker`] per endpoint direction (plus one for bus events), woken from
+//! [`UsbBus::on_interrupt`], and futures that retry the existing non-blocking `read`/`write`
+//! until they stop returning [`UsbError::WouldBlock`].
+//!
+//! This lets an application `.await` endpoint traffic from an executor instead of busy-spinning
+//! `UsbBus::poll()` in the main loop. See [`UsbBus::on_interrupt`] for the locking contract this
+//! relies on.
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use cortex_m::interrupt;
+use usb_device::{Result, UsbError};
+use usb_device::bus::UsbBus as UsbBusTrait;
+use usb_device::endpoint::EndpointAddress;
+use embedded_hal::blocking::delay::{DelayUs, DelayMs};
+use bus::UsbBus;
+use endpoint::NUM_ENDPOINTS;
+use peripheral::UsbPeripheral;
+
+/// One waker per IN endpoint, one per OUT endpoint, and one for bus-level events.
+pub const WAKER_COUNT: usize = NUM_ENDPOINTS * 2 + 1;
+
+pub(crate) const BUS_WAKER: usize = NUM_ENDPOINTS * 2;
+
+fn in_waker(ep: EndpointAddress) -> usize {
+    ep.index() * 2
+}
+
+fn out_waker(ep: EndpointAddress) -> usize {
+    ep.index() * 2 + 1
+}
+
+/// A single-slot waker cell: registering a new waker replaces any previous one, and waking takes
+/// the slot so a concurrent `register` can't race with a `wake`. Mirrors `futures::task::AtomicWaker`
+/// without pulling in the `futures` crate.
+pub struct AtomicWaker {
+    registered: AtomicBool,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+unsafe impl Sync for AtomicWaker {}
+
+impl AtomicWaker {
+    pub const fn new() -> Self {
+        AtomicWaker {
+            registered: AtomicBool::new(false),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    pub fn register(&self, waker: &Waker) {
+        interrupt::free(|_| {
+            unsafe { *self.waker.get() = Some(waker.clone()); }
+            self.registered.store(true, Ordering::Release);
+        });
+    }
+
+    pub fn wake(&self) {
+        interrupt::free(|_| {
+            if self.registered.swap(false, Ordering::AcqRel) {
+                if let Some(waker) = unsafe { (*self.waker.get()).take() } {
+                    waker.wake();
+                }
+            }
+        });
+    }
+}
+
+struct EndpointRead<'a, P: UsbPeripheral, D: DelayUs<u8> + DelayMs<u8>> {
+    bus: &'a UsbBus<P, D>,
+    ep: EndpointAddress,
+    buf: &'a mut [u8],
+}
+
+impl<'a, P: UsbPeripheral, D: DelayUs<u8> + DelayMs<u8>> Future for EndpointRead<'a, P, D> {
+    type Output = Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        // Register before attempting the read, not after: if `on_interrupt` fired in the gap
+        // between a failed attempt and `register`, the wake would hit an empty slot and this
+        // future would park forever.
+        this.bus.wakers[out_waker(this.ep)].register(cx.waker());
+
+        match this.bus.read(this.ep, this.buf) {
+            Err(UsbError::WouldBlock) => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+struct EndpointWrite<'a, P: UsbPeripheral, D: DelayUs<u8> + DelayMs<u8>> {
+    bus: &'a UsbBus<P, D>,
+    ep: EndpointAddress,
+    buf: &'a [u8],
+}
+
+impl<'a, P: UsbPeripheral, D: DelayUs<u8> + DelayMs<u8>> Future for EndpointWrite<'a, P, D> {
+    type Output = Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        // See the comment in `EndpointRead::poll`: register before attempting the write so a
+        // wake landing in the gap between attempt and register isn't lost.
+        this.bus.wakers[in_waker(this.ep)].register(cx.waker());
+
+        match this.bus.write(this.ep, this.buf) {
+            Err(UsbError::WouldBlock) => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+/// Reads from `ep`, yielding to the executor (and re-polling once [`UsbBus::on_interrupt`] wakes
+/// this endpoint) instead of returning [`UsbError::WouldBlock`].
+pub async fn read<'a, P: UsbPeripheral, D: DelayUs<u8> + DelayMs<u8>>(
+    bus: &'a UsbBus<P, D>, ep: EndpointAddress, buf: &'a mut [u8]) -> Result<usize>
+{
+    EndpointRead { bus, ep, buf }.await
+}
+
+/// Writes to `ep`, yielding to the executor instead of returning [`UsbError::WouldBlock`].
+pub async fn write<'a, P: UsbPeripheral, D: DelayUs<u8> + DelayMs<u8>>(
+    bus: &'a UsbBus<P, D>, ep: EndpointAddress, buf: &'a [u8]) -> Result<usize>
+{
+    EndpointWrite { bus, ep, buf }.await
+}
@@ -0,0 +1,131 @@
+//! A USB CDC-ACM serial port backed by ring buffers, borrowing the ring-buffer approach from
+//! embassy's `usb_serial`. Unlike [`cdc_acm::SerialPort`], whose single-packet `Buf` can
+//! only hold one OUT packet at a time and copies it with an O(n) `rotate_left` per partial read,
+//! this class drains/fills the endpoints into contiguous RX/TX rings on every `poll()`, so
+//! `read`/`write` just copy against the rings and never block on USB packet boundaries.
+
+use core::cell::RefCell;
+use usb_device::{Result, UsbError, UsbBus, EndpointType, EndpointPair, EndpointIn, EndpointOut};
+use usb_device::class::{UsbClass, ControlOutResult, DescriptorWriter};
+use usb_device::control::*;
+use cdc_acm::write_cdc_acm_descriptors;
+use ring_buffer::RingBuffer;
+
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// A USB CDC-ACM serial port backed by fixed-capacity RX/TX ring buffers.
+pub struct BufferedSerialPort<'a, B: 'a + UsbBus> {
+    comm_ep: EndpointIn<'a, B>,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+
+    rx: RefCell<RingBuffer>,
+    tx: RefCell<RingBuffer>,
+}
+
+impl<'a, B: UsbBus> BufferedSerialPort<'a, B> {
+    pub fn new(eps: (EndpointPair<'a, B>, EndpointPair<'a, B>))
+        -> BufferedSerialPort<'a, B>
+    {
+        let (_, comm_ep) = eps.0.split(EndpointType::Interrupt, 8);
+        let (read_ep, write_ep) = eps.1.split(EndpointType::Bulk, 64);
+
+        BufferedSerialPort {
+            comm_ep,
+            read_ep,
+            write_ep,
+            rx: RefCell::new(RingBuffer::new()),
+            tx: RefCell::new(RingBuffer::new()),
+        }
+    }
+
+    /// Copies as many buffered RX bytes into `data` as are available, without blocking.
+    pub fn read(&self, data: &mut [u8]) -> Result<usize> {
+        Ok(self.rx.borrow_mut().pop_slice(data))
+    }
+
+    /// Copies as much of `data` into the TX ring as fits, without blocking. The bytes are sent
+    /// opportunistically from `poll()`.
+    pub fn write(&self, data: &[u8]) -> Result<usize> {
+        Ok(self.tx.borrow_mut().push_slice(data))
+    }
+
+    /// Drops any buffered RX and TX data.
+    pub fn clear(&self) {
+        self.rx.borrow_mut().clear();
+        self.tx.borrow_mut().clear();
+    }
+
+    fn fill_rx(&self) {
+        let mut rx = self.rx.borrow_mut();
+
+        // A packet is at most 64 bytes; only pull one off the endpoint once it's guaranteed to
+        // fit whole, since `read_ep.read` has already consumed it and there's nowhere left to
+        // put any part that doesn't fit.
+        if rx.free() < 64 {
+            return;
+        }
+
+        let mut packet = [0u8; 64];
+        match self.read_ep.read(&mut packet) {
+            Ok(count) => { rx.push_slice(&packet[0..count]); },
+            Err(UsbError::WouldBlock) => { },
+            Err(_) => { },
+        }
+    }
+
+    fn flush_tx(&self) {
+        let mut tx = self.tx.borrow_mut();
+
+        if tx.is_empty() {
+            return;
+        }
+
+        // Peek rather than pop so a busy endpoint leaves the bytes at the front of the ring,
+        // in order, for the next poll instead of requeuing them behind whatever is still buffered.
+        let mut packet = [0u8; 64];
+        let count = tx.peek_slice(&mut packet);
+
+        match self.write_ep.write(&packet[0..count]) {
+            Ok(_) => { tx.consume(count); },
+            Err(UsbError::WouldBlock) => { },
+            Err(_) => { },
+        }
+    }
+}
+
+impl<'a, B: UsbBus> UsbClass for BufferedSerialPort<'a, B> {
+    fn reset(&self) -> Result<()> {
+        self.comm_ep.configure()?;
+        self.read_ep.configure()?;
+        self.write_ep.configure()?;
+
+        self.clear();
+
+        Ok(())
+    }
+
+    fn poll(&self) {
+        self.fill_rx();
+        self.flush_tx();
+    }
+
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        write_cdc_acm_descriptors(writer, &self.comm_ep, &self.read_ep, &self.write_ep)
+    }
+
+    fn control_out(&self, req: &Request, buf: &[u8]) -> ControlOutResult {
+        let _ = buf;
+
+        if req.request_type == RequestType::Class && req.recipient == Recipient::Interface {
+            return match req.request {
+                REQ_SET_LINE_CODING => ControlOutResult::Ok,
+                REQ_SET_CONTROL_LINE_STATE => ControlOutResult::Ok,
+                _ => ControlOutResult::Ignore,
+            };
+        }
+
+        ControlOutResult::Ignore
+    }
+}
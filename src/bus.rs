@@ -1,44 +1,58 @@
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use core::mem;
+use core::ptr;
+use embedded_hal::blocking::delay::{DelayUs, DelayMs};
 use usb_device::{Result, UsbError};
 use usb_device::bus::{UsbBusWrapper, PollResult};
 use usb_device::endpoint::{EndpointDirection, EndpointType, EndpointAddress};
 use usb_device::utils::{FreezableRefCell, AtomicMutex};
 //use bare_metal::Mutex;
-use cortex_m::asm::delay;
 use cortex_m::interrupt;
-use stm32f103xx::USB;
 use stm32f103xx_hal::prelude::*;
 use stm32f103xx_hal::rcc;
 use stm32f103xx_hal::gpio::{self, gpioa};
 use endpoint::{NUM_ENDPOINTS, Endpoint, EndpointStatus, calculate_count_rx};
+use asynch::{AtomicWaker, WAKER_COUNT, BUS_WAKER};
+use peripheral::UsbPeripheral;
 
 struct Reset {
-    delay: u32,
+    delay_ms: u8,
     pin: RefCell<gpioa::PA12<gpio::Output<gpio::PushPull>>>,
 }
 
 /// USB peripheral driver for STM32F103 microcontrollers.
-pub struct UsbBus {
-    regs: AtomicMutex<USB>,
+///
+/// Generic over the register block (`P`, anything implementing [`UsbPeripheral`]) so the same
+/// driver serves pin-remap variants and downstream forks without copy-paste, and over an
+/// `embedded-hal` delay (`D`) so the mandatory power-on and reset-pulse timings are specified in
+/// real microseconds/milliseconds instead of a core-frequency-dependent cycle count.
+pub struct UsbBus<P: UsbPeripheral, D: DelayUs<u8> + DelayMs<u8>> {
+    regs: AtomicMutex<P>,
+    delay: RefCell<D>,
     endpoints: [Endpoint; NUM_ENDPOINTS],
     next_ep_mem: usize,
     max_endpoint: usize,
     reset: FreezableRefCell<Option<Reset>>,
+    // One waker per IN endpoint, one per OUT endpoint, and one for bus-level events (reset,
+    // suspend, resume). Woken from `on_interrupt`; see its doc comment for the locking contract.
+    pub(crate) wakers: [AtomicWaker; WAKER_COUNT],
+    // Tracked from both `poll` and `on_interrupt` so `remote_wakeup` can tell whether driving
+    // `CNTR.RESUME` makes sense.
+    suspended: Cell<bool>,
+    remote_wakeup_enabled: Cell<bool>,
 }
 
-impl UsbBus {
+impl<P: UsbPeripheral, D: DelayUs<u8> + DelayMs<u8>> UsbBus<P, D> {
     /// Constructs a new USB peripheral driver.
-    pub fn usb(regs: USB, apb1: &mut rcc::APB1) -> UsbBusWrapper<Self> {
-        // TODO: apb1.enr is not public, figure out how this should really interact with the HAL
-        // crate
-
-        interrupt::free(|_| {
-            apb1.enr().modify(|_, w| w.usben().enabled());
-        });
+    ///
+    /// `delay` provides the ~1µs power-on wait in `enable()` and, once `enable_reset` is called,
+    /// the reset-pulse wait in `force_reset()`.
+    pub fn usb(regs: P, delay: D, apb1: &mut rcc::APB1) -> UsbBusWrapper<Self> {
+        P::enable_clock(apb1);
 
         let bus = UsbBus {
             regs: AtomicMutex::new(regs),
+            delay: RefCell::new(delay),
             next_ep_mem: Endpoint::MEM_START,
             max_endpoint: 0,
             endpoints: unsafe {
@@ -51,21 +65,159 @@ impl UsbBus {
                 endpoints
             },
             reset: FreezableRefCell::default(),
+            wakers: unsafe {
+                let mut wakers: [AtomicWaker; WAKER_COUNT] = mem::uninitialized();
+
+                for w in wakers.iter_mut() {
+                    ptr::write(w, AtomicWaker::new());
+                }
+
+                wakers
+            },
+            suspended: Cell::new(false),
+            remote_wakeup_enabled: Cell::new(false),
         };
 
         UsbBusWrapper::new(bus)
     }
 
-    /// Enables the `reset` method.
+    /// Tracks whether the host has enabled remote wakeup via `SET_FEATURE(DEVICE_REMOTE_WAKEUP)`.
+    /// Call this from the `SET_FEATURE`/`CLEAR_FEATURE` handling for that feature; `remote_wakeup`
+    /// refuses to signal resume while this is `false`.
+    pub fn set_remote_wakeup_enabled(&self, enabled: bool) {
+        self.remote_wakeup_enabled.set(enabled);
+    }
+
+    /// Wakes a suspended host by driving `CNTR.RESUME` (K-state) for a few milliseconds, as
+    /// required to initiate device-side remote wakeup.
+    ///
+    /// This is a no-op (returns `Err(UsbError::Unsupported)`) unless the bus is currently
+    /// suspended and the host has enabled remote wakeup, since signalling resume otherwise would
+    /// violate the USB spec. Nothing in this crate calls `set_remote_wakeup_enabled` on its own,
+    /// since that depends on the device's `SET_FEATURE`/`CLEAR_FEATURE(DEVICE_REMOTE_WAKEUP)`
+    /// handling, which lives above this driver; the application (or the `usb_device` control
+    /// pipeline) must call it for this guard to ever pass.
+    pub fn remote_wakeup(&self) -> Result<()> {
+        if !self.suspended.get() || !self.remote_wakeup_enabled.get() {
+            return Err(UsbError::Unsupported);
+        }
+
+        interrupt::free(|_| {
+            let regs = self.regs.try_lock().unwrap();
+
+            // Leave the low-power state entered by `suspend()` before driving resume signalling,
+            // per the reference manual's remote wakeup sequence.
+            regs.cntr.modify(|_, w| w.lpmode().clear_bit());
+            regs.cntr.modify(|_, w| w.resume().set_bit());
+
+            // The spec requires the device to drive resume signalling for at least 1ms, and at
+            // most 15ms before the host takes over; 5ms comfortably clears the low end.
+            self.delay.borrow_mut().delay_ms(5u8);
+
+            regs.cntr.modify(|_, w| w.resume().clear_bit().fsusp().clear_bit());
+        });
+
+        self.suspended.set(false);
+
+        Ok(())
+    }
+
+    /// Enables the `force_reset` method. `reset_delay_ms` is the reset pulse width to hold the
+    /// `D-`/`D+` pull-up pin low for; a few milliseconds is enough to make the host notice the
+    /// device has disconnected and reconnected.
     pub fn enable_reset<M>(&mut self,
-        clocks: &rcc::Clocks, crh: &mut gpioa::CRH, pa12: gpioa::PA12<M>)
+        reset_delay_ms: u8, crh: &mut gpioa::CRH, pa12: gpioa::PA12<M>)
     {
         *self.reset.borrow_mut() = Some(Reset {
-            delay: clocks.sysclk().0,
+            delay_ms: reset_delay_ms,
             pin: RefCell::new(pa12.into_push_pull_output(crh)),
         });
     }
 
+    /// Reads and clears pending interrupt flags exactly as [`poll`](::usb_device::bus::UsbBus::poll)
+    /// does, but instead of returning a `PollResult`, wakes the futures returned by
+    /// [`asynch::read`](::asynch::read)/[`asynch::write`](::asynch::write) so they can re-poll
+    /// the endpoint.
+    ///
+    /// Call this from the USB interrupt handler to drive the async API without busy-spinning
+    /// `poll()` from the main loop. Both paths take the same peripheral lock via
+    /// [`AtomicMutex::try_lock`], so `on_interrupt` and `poll` must not be called concurrently:
+    /// if `poll()` is still running when the interrupt fires (or vice versa), the loser's call
+    /// silently becomes a no-op for that invocation, and whichever side is left running will
+    /// observe and clear the flags on its next call. In practice this means applications should
+    /// pick exactly one of the two driving styles (synchronous `poll()` from the main loop, or
+    /// `on_interrupt()` from the IRQ plus the `asynch` futures) rather than mixing them for the
+    /// same endpoint.
+    ///
+    /// A completed single-buffered OUT transaction is masked (see `Endpoint::mask_out`) rather
+    /// than left alone (a double-buffered one just has its `CTR_RX` cleared),
+    /// since `CTR_RX` is read-only in `ISTR.CTR` and only deasserts once the endpoint's own
+    /// `CTR_RX` bit is cleared: leaving it set here until `asynch::read` clears it from thread
+    /// mode would mean the core tail-chains straight back into this handler instead, an
+    /// interrupt storm that never gives the executor a chance to run the future at all. See
+    /// `examples/serial_interrupt.rs` for a worked example of this driving style.
+    pub fn on_interrupt(&self) {
+        let mut guard = self.regs.try_lock();
+
+        let regs = match guard {
+            Some(ref mut r) => r,
+            None => return,
+        };
+
+        let istr = regs.istr.read();
+
+        if istr.wkup().bit_is_set() {
+            regs.istr.modify(|_, w| w.wkup().clear_bit());
+
+            let fnr = regs.fnr.read();
+            match (fnr.rxdp().bit_is_set(), fnr.rxdm().bit_is_set()) {
+                (false, false) | (false, true) => self.suspended.set(false),
+                // Spurious wakeup event caused by noise
+                _ => self.suspended.set(true),
+            }
+
+            self.wakers[BUS_WAKER].wake();
+        } else if istr.reset().bit_is_set() {
+            regs.istr.modify(|_, w| w.reset().clear_bit());
+            self.suspended.set(false);
+            self.wakers[BUS_WAKER].wake();
+        } else if istr.susp().bit_is_set() {
+            regs.istr.modify(|_, w| w.susp().clear_bit());
+            self.suspended.set(true);
+            self.wakers[BUS_WAKER].wake();
+        } else if istr.ctr().bit_is_set() {
+            for (index, ep) in self.endpoints[0..=self.max_endpoint].iter().enumerate() {
+                let v = ep.read_reg();
+
+                if v.ctr_rx().bit_is_set() {
+                    interrupt::free(|cs| {
+                        if ep.is_double_buffered() {
+                            // The other buffer keeps accepting packets while this one waits to be
+                            // drained, so clearing CTR_RX here can't lose data the way it would
+                            // for a single-buffered endpoint.
+                            ep.clear_ctr_rx(cs);
+                        } else {
+                            // CTR_RX is read-only in ISTR.CTR and only deasserts once the
+                            // endpoint's CTR_RX bit is cleared. Clearing it from `read` isn't an
+                            // option here: `read` runs later in thread mode once the future is
+                            // polled, and until then ISTR.CTR would stay set and the core would
+                            // tail-chain straight back into this handler. `mask_out` clears it
+                            // now without losing the packet, by forcing STAT_RX to Nak first.
+                            ep.mask_out(cs);
+                        }
+                    });
+
+                    self.wakers[index * 2 + 1].wake();
+                }
+
+                if v.ctr_tx().bit_is_set() {
+                    interrupt::free(|cs| ep.clear_ctr_tx(cs));
+                    self.wakers[index * 2].wake();
+                }
+            }
+        }
+    }
+
     fn alloc_ep_mem(next_ep_mem: &mut usize, size: usize) -> Result<usize> {
         assert!(size & 1 == 0);
 
@@ -80,17 +232,39 @@ impl UsbBus {
     }
 }
 
-impl ::usb_device::bus::UsbBus for UsbBus {
-    fn alloc_ep(
+impl<P: UsbPeripheral, D: DelayUs<u8> + DelayMs<u8>> UsbBus<P, D> {
+    /// Like `alloc_ep`, but always double-buffers the allocated endpoint instead of leaving that
+    /// decision to the endpoint type. `Isochronous` endpoints go through `alloc_ep` directly and
+    /// are always double-buffered there, since the peripheral requires it; this entry point is
+    /// for `Bulk` endpoints that want double buffering for throughput. Double-buffered endpoints
+    /// consume both buffer slots of their BTABLE entry, so the opposite direction of the same
+    /// endpoint index cannot also be allocated.
+    pub fn alloc_double_buffered_ep(
         &mut self,
         ep_dir: EndpointDirection,
         ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16) -> Result<EndpointAddress>
+    {
+        if max_packet_size as usize > Endpoint::MEM_SIZE / 2 {
+            return Err(UsbError::SizeOverflow);
+        }
+
+        Self::alloc_ep_inner(
+            &mut self.endpoints, &mut self.next_ep_mem,
+            ep_dir, ep_addr, EndpointType::Bulk, max_packet_size, true)
+    }
+
+    fn alloc_ep_inner(
+        endpoints: &mut [Endpoint; NUM_ENDPOINTS],
+        next_ep_mem: &mut usize,
+        ep_dir: EndpointDirection,
+        ep_addr: Option<EndpointAddress>,
         ep_type: EndpointType,
         max_packet_size: u16,
-        _interval: u8) -> Result<EndpointAddress>
+        double_buffered: bool) -> Result<EndpointAddress>
     {
         for index in ep_addr.map(|a| a.index()..a.index()+1).unwrap_or(1..NUM_ENDPOINTS) {
-            let ep = &mut self.endpoints[index];
+            let ep = &mut endpoints[index];
 
             match ep.ep_type() {
                 None => { ep.set_ep_type(ep_type); },
@@ -98,20 +272,45 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                 _ => { },
             };
 
+            if ep.is_double_buffered() {
+                // Already owns both BTABLE buffer slots for its one direction.
+                continue;
+            }
+
+            if double_buffered {
+                if ep.is_out_buf_set() || ep.is_in_buf_set() {
+                    // Double-buffered endpoints are unidirectional and own both BTABLE buffer
+                    // slots, so they can't coexist with a buffer in the opposite direction.
+                    continue;
+                }
+
+                ep.set_double_buffered();
+            }
+
             match ep_dir {
                 EndpointDirection::Out if !ep.is_out_buf_set() => {
                     let (out_size, bits) = calculate_count_rx(max_packet_size as usize)?;
 
-                    let addr = Self::alloc_ep_mem(&mut self.next_ep_mem, out_size)?;
+                    let addr0 = Self::alloc_ep_mem(next_ep_mem, out_size)?;
+                    let addr1 = if double_buffered {
+                        Some(Self::alloc_ep_mem(next_ep_mem, out_size)?)
+                    } else {
+                        None
+                    };
 
-                    ep.set_out_buf(addr, (out_size, bits));
+                    ep.set_out_buf(addr0, addr1, (out_size, bits));
 
                     return Ok(EndpointAddress::from_parts(index, ep_dir));
                 },
                 EndpointDirection::In if !ep.is_in_buf_set() => {
-                    let addr = Self::alloc_ep_mem(&mut self.next_ep_mem, max_packet_size as usize)?;
+                    let addr0 = Self::alloc_ep_mem(next_ep_mem, max_packet_size as usize)?;
+                    let addr1 = if double_buffered {
+                        Some(Self::alloc_ep_mem(next_ep_mem, max_packet_size as usize)?)
+                    } else {
+                        None
+                    };
 
-                    ep.set_in_buf(addr, max_packet_size as usize);
+                    ep.set_in_buf(addr0, addr1, max_packet_size as usize);
 
                     return Ok(EndpointAddress::from_parts(index, ep_dir));
                 }
@@ -121,6 +320,24 @@ impl ::usb_device::bus::UsbBus for UsbBus {
 
         Err(UsbError::EndpointOverflow)
     }
+}
+
+impl<P: UsbPeripheral, D: DelayUs<u8> + DelayMs<u8>> ::usb_device::bus::UsbBus for UsbBus<P, D> {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: EndpointDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8) -> Result<EndpointAddress>
+    {
+        // Isochronous transfers are required to be double-buffered on this peripheral.
+        let double_buffered = ep_type == EndpointType::Isochronous;
+
+        Self::alloc_ep_inner(
+            &mut self.endpoints, &mut self.next_ep_mem,
+            ep_dir, ep_addr, ep_type, max_packet_size, double_buffered)
+    }
 
     fn enable(&mut self) {
         self.reset.freeze();
@@ -141,7 +358,7 @@ impl ::usb_device::bus::UsbBus for UsbBus {
 
             // There is a chip specific startup delay. For STM32F103xx it's 1µs and this should wait for
             // at least that long.
-            delay(72);
+            self.delay.borrow_mut().delay_us(1u8);
 
             regs.btable.modify(|_, w| unsafe { w.btable().bits(0) });
             regs.cntr.modify(|_, w| w.fres().clear_bit());
@@ -160,6 +377,9 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                 ep.configure(cs);
             }
         });
+
+        self.suspended.set(false);
+        self.remote_wakeup_enabled.set(false);
     }
 
     fn set_device_address(&self, addr: u8) {
@@ -168,6 +388,8 @@ impl ::usb_device::bus::UsbBus for UsbBus {
         });
     }
 
+    /// See the locking contract documented on [`on_interrupt`](Self::on_interrupt) if the
+    /// application also drives the peripheral from the USB interrupt.
     fn poll(&self) -> PollResult {
         let mut guard = self.regs.try_lock();
 
@@ -188,19 +410,23 @@ impl ::usb_device::bus::UsbBus for UsbBus {
 
             match (fnr.rxdp().bit_is_set(), fnr.rxdm().bit_is_set()) {
                 (false, false) | (false, true) => {
+                    self.suspended.set(false);
                     PollResult::Resume
                 },
                 _ => {
                     // Spurious wakeup event caused by noise
+                    self.suspended.set(true);
                     PollResult::Suspend
                 }
             }
         } else if istr.reset().bit_is_set() {
             regs.istr.modify(|_, w| w.reset().clear_bit());
+            self.suspended.set(false);
 
             PollResult::Reset
         } else if istr.susp().bit_is_set() {
             regs.istr.modify(|_, w| w.susp().clear_bit());
+            self.suspended.set(true);
 
             PollResult::Suspend
         } else if istr.ctr().bit_is_set() {
@@ -288,6 +514,8 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                 .fsusp().set_bit()
                 .lpmode().set_bit());
         });
+
+        self.suspended.set(true);
     }
 
     fn resume(&self) {
@@ -296,6 +524,8 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                 .fsusp().clear_bit()
                 .lpmode().clear_bit());
         });
+
+        self.suspended.set(false);
     }
 
     fn force_reset(&self) -> Result<()> {
@@ -308,7 +538,7 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                     regs.cntr.modify(|_, w| w.pdwn().set_bit());
 
                     reset.pin.borrow_mut().set_low();
-                    delay(reset.delay);
+                    self.delay.borrow_mut().delay_ms(reset.delay_ms);
 
                     regs.cntr.modify(|_, w| w.pdwn().bit(pdwn));
 
@@ -0,0 +1,311 @@
+//! A USB CDC-ACM (virtual serial port) class, modeled on usbd-serial's `cdc_acm.rs`.
+
+use core::cell::{Cell, RefCell};
+use usb_device::{Result, UsbError, UsbBus, EndpointType, EndpointPair, EndpointIn, EndpointOut};
+use usb_device::class::{UsbClass, ControlInResult, ControlOutResult, DescriptorWriter};
+use usb_device::control::*;
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_DATA: u8 = 0x0a;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_PROTOCOL_AT: u8 = 0x01;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// CDC `SERIAL_STATE` notification code, sent over `comm_ep` by [`SerialPort::write_serial_state`].
+const NOTIFICATION_SERIAL_STATE: u8 = 0x20;
+
+/// `UART_STATE` bitmap bits used by the `SERIAL_STATE` notification (CDC120 table 69).
+pub const SERIAL_STATE_DCD: u16 = 1 << 0;
+pub const SERIAL_STATE_DSR: u16 = 1 << 1;
+pub const SERIAL_STATE_BREAK: u16 = 1 << 2;
+pub const SERIAL_STATE_RING: u16 = 1 << 3;
+
+const COMM_INTERFACE_NUM: u8 = 1;
+
+/// `bCharFormat` values from the CDC `SET_LINE_CODING`/`GET_LINE_CODING` payload.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum StopBits {
+    One = 0,
+    OnePointFive = 1,
+    Two = 2,
+}
+
+impl From<u8> for StopBits {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => StopBits::OnePointFive,
+            2 => StopBits::Two,
+            _ => StopBits::One,
+        }
+    }
+}
+
+/// `bParityType` values from the CDC `SET_LINE_CODING`/`GET_LINE_CODING` payload.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum ParityType {
+    None = 0,
+    Odd = 1,
+    Even = 2,
+    Mark = 3,
+    Space = 4,
+}
+
+impl From<u8> for ParityType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => ParityType::Odd,
+            2 => ParityType::Even,
+            3 => ParityType::Mark,
+            4 => ParityType::Space,
+            _ => ParityType::None,
+        }
+    }
+}
+
+/// The 7-byte line coding structure carried by `SET_LINE_CODING`/`GET_LINE_CODING`.
+#[derive(Copy, Clone)]
+pub struct LineCoding {
+    data_rate: u32,
+    stop_bits: StopBits,
+    parity_type: ParityType,
+    data_bits: u8,
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        LineCoding {
+            data_rate: 8_000,
+            stop_bits: StopBits::One,
+            parity_type: ParityType::None,
+            data_bits: 8,
+        }
+    }
+}
+
+impl LineCoding {
+    pub fn data_rate(&self) -> u32 { self.data_rate }
+    pub fn stop_bits(&self) -> StopBits { self.stop_bits }
+    pub fn parity_type(&self) -> ParityType { self.parity_type }
+    pub fn data_bits(&self) -> u8 { self.data_bits }
+}
+
+/// Writes the CDC-ACM interface descriptors shared by [`SerialPort`] and
+/// [`buffered_serial::BufferedSerialPort`]: a data interface for `read_ep`/`write_ep` and
+/// a comm interface for `comm_ep`, with the functional descriptors a host needs to recognize this
+/// as an ACM device.
+pub(crate) fn write_cdc_acm_descriptors<B: UsbBus>(
+    writer: &mut DescriptorWriter,
+    comm_ep: &EndpointIn<B>,
+    read_ep: &EndpointOut<B>,
+    write_ep: &EndpointIn<B>) -> Result<()>
+{
+    // TODO: make a better DescriptorWriter to make it harder to make invalid descriptors
+    let data_if = writer.interface(
+        2,
+        USB_CLASS_DATA,
+        0x00,
+        0x00)?;
+
+    writer.endpoint(write_ep)?;
+    writer.endpoint(read_ep)?;
+
+    let comm_if = writer.interface(
+        COMM_INTERFACE_NUM,
+        USB_CLASS_CDC,
+        CDC_SUBCLASS_ACM,
+        CDC_PROTOCOL_AT)?;
+
+    writer.endpoint(comm_ep)?;
+
+    writer.write(
+        CS_INTERFACE,
+        &[CDC_TYPE_HEADER, 0x10, 0x01])?;
+
+    writer.write(
+        CS_INTERFACE,
+        &[CDC_TYPE_CALL_MANAGEMENT, 0x00, data_if])?;
+
+    writer.write(
+        CS_INTERFACE,
+        &[CDC_TYPE_ACM, 0x00])?;
+
+    writer.write(
+        CS_INTERFACE,
+        &[CDC_TYPE_UNION, comm_if, data_if])?;
+
+    Ok(())
+}
+
+struct Buf {
+    buf: [u8; 64],
+    len: usize,
+}
+
+/// A USB CDC-ACM serial port.
+pub struct SerialPort<'a, B: 'a + UsbBus> {
+    comm_ep: EndpointIn<'a, B>,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+
+    read_buf: RefCell<Buf>,
+    line_coding: RefCell<LineCoding>,
+    dtr: Cell<bool>,
+    rts: Cell<bool>,
+}
+
+impl<'a, B: UsbBus> SerialPort<'a, B> {
+    pub fn new(eps: (EndpointPair<'a, B>, EndpointPair<'a, B>))
+        -> SerialPort<'a, B>
+    {
+        // 16 rather than 8: a `SERIAL_STATE` notification is 10 bytes and must fit one packet.
+        let (_, comm_ep) = eps.0.split(EndpointType::Interrupt, 16);
+        let (read_ep, write_ep) = eps.1.split(EndpointType::Bulk, 64);
+
+        SerialPort {
+            comm_ep,
+            read_ep,
+            write_ep,
+            read_buf: RefCell::new(Buf {
+                buf: [0; 64],
+                len: 0,
+            }),
+            line_coding: RefCell::new(LineCoding::default()),
+            dtr: Cell::new(false),
+            rts: Cell::new(false),
+        }
+    }
+
+    pub fn write(&self, data: &[u8]) -> Result<usize> {
+        match self.write_ep.write(data) {
+            Ok(count) => Ok(count),
+            Err(UsbError::WouldBlock) => Ok(0),
+            e => e,
+        }
+    }
+
+    pub fn read(&self, data: &mut [u8]) -> Result<usize> {
+        let mut buf = self.read_buf.borrow_mut();
+
+        // Terrible buffering implementation for brevity's sake
+
+        if buf.len == 0 {
+            buf.len = match self.read_ep.read(&mut buf.buf) {
+                Ok(count) => count,
+                Err(UsbError::WouldBlock) => return Ok(0),
+                e => return e,
+            };
+        }
+
+        if buf.len == 0 {
+            return Ok(0);
+        }
+
+        let count = core::cmp::min(data.len(), buf.len);
+
+        &data[..count].copy_from_slice(&buf.buf[0..count]);
+
+        buf.buf.rotate_left(count);
+        buf.len -= count;
+
+        Ok(count)
+    }
+
+    /// The line coding last set by the host via `SET_LINE_CODING`.
+    pub fn line_coding(&self) -> LineCoding {
+        *self.line_coding.borrow()
+    }
+
+    /// Whether the host has asserted DTR via `SET_CONTROL_LINE_STATE`.
+    pub fn dtr(&self) -> bool {
+        self.dtr.get()
+    }
+
+    /// Whether the host has asserted RTS via `SET_CONTROL_LINE_STATE`.
+    pub fn rts(&self) -> bool {
+        self.rts.get()
+    }
+
+    /// Sends a CDC `SERIAL_STATE` notification over `comm_ep` so the host learns about a change
+    /// in DCD/DSR/break/ring state. `state` is an OR of the `SERIAL_STATE_*` bits.
+    pub fn write_serial_state(&self, state: u16) -> Result<usize> {
+        let data = [
+            0xa1, NOTIFICATION_SERIAL_STATE,
+            0x00, 0x00,
+            COMM_INTERFACE_NUM, 0x00,
+            0x02, 0x00,
+            state as u8, (state >> 8) as u8,
+        ];
+
+        self.comm_ep.write(&data)
+    }
+}
+
+impl<'a, B: UsbBus> UsbClass for SerialPort<'a, B> {
+    fn reset(&self) -> Result<()> {
+        self.comm_ep.configure()?;
+        self.read_ep.configure()?;
+        self.write_ep.configure()?;
+
+        Ok(())
+    }
+
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        write_cdc_acm_descriptors(writer, &self.comm_ep, &self.read_ep, &self.write_ep)
+    }
+
+    fn control_in(&self, req: &Request, buf: &mut [u8]) -> ControlInResult {
+        if req.request_type == RequestType::Class && req.recipient == Recipient::Interface {
+            return match req.request {
+                REQ_GET_LINE_CODING if buf.len() >= 7 => {
+                    let coding = self.line_coding.borrow();
+
+                    let rate = coding.data_rate.to_le_bytes();
+                    buf[0..4].copy_from_slice(&rate);
+                    buf[4] = coding.stop_bits as u8;
+                    buf[5] = coding.parity_type as u8;
+                    buf[6] = coding.data_bits;
+
+                    ControlInResult::Ok(7)
+                },
+                _ => ControlInResult::Ignore,
+            };
+        }
+
+        ControlInResult::Ignore
+    }
+
+    fn control_out(&self, req: &Request, buf: &[u8]) -> ControlOutResult {
+        if req.request_type == RequestType::Class && req.recipient == Recipient::Interface {
+            return match req.request {
+                REQ_SET_LINE_CODING if buf.len() >= 7 => {
+                    let mut coding = self.line_coding.borrow_mut();
+
+                    coding.data_rate = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+                    coding.stop_bits = StopBits::from(buf[4]);
+                    coding.parity_type = ParityType::from(buf[5]);
+                    coding.data_bits = buf[6];
+
+                    ControlOutResult::Ok
+                },
+                REQ_SET_CONTROL_LINE_STATE => {
+                    self.dtr.set(req.value & 0x0001 != 0);
+                    self.rts.set(req.value & 0x0002 != 0);
+
+                    ControlOutResult::Ok
+                },
+                _ => ControlOutResult::Ignore,
+            };
+        }
+
+        ControlOutResult::Ignore
+    }
+}
@@ -0,0 +1,373 @@
+use core::cell::Cell;
+use core::ptr;
+use usb_device::{Result, UsbError};
+use usb_device::endpoint::EndpointType;
+use cortex_m::interrupt::CriticalSection;
+use stm32f103xx::usb::EP0R;
+
+/// Number of hardware endpoints implemented by the F103's USB peripheral (EP0..EP7).
+pub const NUM_ENDPOINTS: usize = 8;
+
+// The packet memory area (PMA) is accessed through the AHB bus as an array of 16-bit words, but
+// each word occupies a 32-bit slot (the upper half of each slot is unused). The BTABLE lives at
+// the start of this area and describes where each endpoint's buffer(s) live within it.
+const PMA_ADDR: usize = 0x4000_6000;
+const PMA_SIZE: usize = 512;
+
+fn pma_read16(offset: usize) -> u16 {
+    unsafe { ptr::read_volatile((PMA_ADDR + offset * 4) as *const u16) }
+}
+
+fn pma_write16(offset: usize, value: u16) {
+    unsafe { ptr::write_volatile((PMA_ADDR + offset * 4) as *mut u16, value) };
+}
+
+fn pma_read(addr: usize, buf: &mut [u8]) {
+    let mut offset = addr / 2;
+
+    for chunk in buf.chunks_mut(2) {
+        let word = pma_read16(offset);
+        chunk[0] = word as u8;
+        if chunk.len() > 1 {
+            chunk[1] = (word >> 8) as u8;
+        }
+        offset += 1;
+    }
+}
+
+fn pma_write(addr: usize, buf: &[u8]) {
+    let mut offset = addr / 2;
+
+    for chunk in buf.chunks(2) {
+        let word = chunk[0] as u16 | ((*chunk.get(1).unwrap_or(&0) as u16) << 8);
+        pma_write16(offset, word);
+        offset += 1;
+    }
+}
+
+/// Possible states of the `STAT_TX`/`STAT_RX` endpoint status bits.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum EndpointStatus {
+    Disabled = 0b00,
+    Stall = 0b01,
+    Nak = 0b10,
+    Valid = 0b11,
+}
+
+/// Encodes a max packet size into the `COUNT_RX`/BL_SIZE+NUM_BLOCK representation required by the
+/// peripheral. Returns the rounded-up buffer size in bytes and the encoded bit pattern.
+pub fn calculate_count_rx(max_packet_size: usize) -> Result<(usize, usize)> {
+    if max_packet_size <= 62 {
+        let size = (max_packet_size + 1) & !0x01;
+        let bits = size << 9;
+
+        Ok((size, bits))
+    } else if max_packet_size <= 1024 {
+        let size = (max_packet_size + 31) & !0x1f;
+        let bits = 0x8000 | ((size - 32) << 5);
+
+        Ok((size, bits))
+    } else {
+        Err(UsbError::EndpointMemoryOverflow)
+    }
+}
+
+// EPnR bit layout, common to EP0R..EP7R. STAT_TX/DTOG_TX/STAT_RX/DTOG_RX are "write 1 to toggle,
+// write 0 to preserve"; CTR_TX/CTR_RX are "write 0 to clear, write 1 to preserve"; EA/EP_KIND/
+// EP_TYPE are plain read-write and must be re-written on every access or they'd be reset to 0.
+const EPR_EA: u16 = 0x000f;
+const EPR_STAT_TX: u16 = 0x0030;
+const EPR_DTOG_TX: u16 = 0x0040;
+const EPR_CTR_TX: u16 = 0x0080;
+const EPR_EP_KIND: u16 = 0x0100;
+const EPR_EP_TYPE: u16 = 0x0600;
+const EPR_STAT_RX: u16 = 0x3000;
+const EPR_DTOG_RX: u16 = 0x4000;
+const EPR_CTR_RX: u16 = 0x8000;
+const EPR_INVARIANT: u16 = EPR_EA | EPR_EP_KIND | EPR_EP_TYPE;
+const EPR_RC_W0: u16 = EPR_CTR_TX | EPR_CTR_RX;
+
+#[derive(Copy, Clone)]
+struct BufDescriptor {
+    /// PMA address of buffer 0 (the only buffer, unless double-buffered).
+    addr0: usize,
+    /// PMA address of buffer 1, for a double-buffered endpoint.
+    addr1: Option<usize>,
+    /// `COUNT_RX` bits for an OUT buffer, or the max packet size in bytes for an IN buffer.
+    size: usize,
+}
+
+/// A single hardware endpoint and the PMA buffer(s) backing it.
+///
+/// Endpoint 0 is always control; the rest are allocated by `UsbBus::alloc_ep` as classes request
+/// them.
+pub struct Endpoint {
+    index: u8,
+    ep_type: Cell<Option<EndpointType>>,
+    out_buf: Cell<Option<BufDescriptor>>,
+    in_buf: Cell<Option<BufDescriptor>>,
+    /// Set by `set_double_buffered`, before the endpoint's buffers are allocated. Double-buffered
+    /// endpoints are unidirectional, so a single software ping-pong flag covers either direction.
+    double_buffered: Cell<bool>,
+    sw_buf: Cell<bool>,
+    /// Set by `mask_out` when `UsbBus::on_interrupt` has cleared `CTR_RX` from the IRQ (to stop
+    /// `ISTR.CTR` restarting the interrupt) before `read` has had a chance to run and drain the
+    /// buffer. `read` checks this instead of `CTR_RX` once it's set, since by then `CTR_RX` is
+    /// already clear even though a packet is still waiting.
+    out_pending: Cell<bool>,
+}
+
+impl Endpoint {
+    /// Start of the endpoint buffer area in the PMA, just past the BTABLE (8 bytes per endpoint).
+    pub const MEM_START: usize = NUM_ENDPOINTS * 8;
+    pub const MEM_SIZE: usize = PMA_SIZE;
+
+    pub fn new(index: u8) -> Endpoint {
+        Endpoint {
+            index,
+            ep_type: Cell::new(None),
+            out_buf: Cell::new(None),
+            in_buf: Cell::new(None),
+            double_buffered: Cell::new(false),
+            sw_buf: Cell::new(false),
+            out_pending: Cell::new(false),
+        }
+    }
+
+    pub fn ep_type(&self) -> Option<EndpointType> {
+        self.ep_type.get()
+    }
+
+    pub fn set_ep_type(&self, ep_type: EndpointType) {
+        self.ep_type.set(Some(ep_type));
+    }
+
+    pub fn is_out_buf_set(&self) -> bool {
+        self.out_buf.get().is_some()
+    }
+
+    pub fn is_in_buf_set(&self) -> bool {
+        self.in_buf.get().is_some()
+    }
+
+    pub fn is_double_buffered(&self) -> bool {
+        self.double_buffered.get()
+    }
+
+    /// Opts this endpoint into double buffering: `configure` then sets `EP_KIND`, and `read`/`write`
+    /// alternate between the `addr0`/`addr1` buffers given to `set_out_buf`/`set_in_buf` (which
+    /// program buffer 1 whenever `addr1` is `Some`). `Isochronous` endpoints always go
+    /// through this path, since the peripheral requires double buffering for isochronous
+    /// transfers; bulk endpoints may opt in via `UsbBus::alloc_double_buffered_ep`.
+    pub fn set_double_buffered(&self) {
+        self.double_buffered.set(true);
+    }
+
+    pub fn set_out_buf(&self, addr0: usize, addr1: Option<usize>, (_size, bits): (usize, usize)) {
+        self.out_buf.set(Some(BufDescriptor { addr0, addr1, size: bits }));
+
+        let btable_index = self.index as usize * 4;
+
+        match addr1 {
+            None => {
+                pma_write16(btable_index + 2, addr0 as u16);
+                pma_write16(btable_index + 3, bits as u16);
+            },
+            Some(addr1) => {
+                // Double-buffered OUT: slot 0/1 hold buffer 0, slot 2/3 hold buffer 1.
+                pma_write16(btable_index, addr0 as u16);
+                pma_write16(btable_index + 1, bits as u16);
+                pma_write16(btable_index + 2, addr1 as u16);
+                pma_write16(btable_index + 3, bits as u16);
+            },
+        }
+    }
+
+    pub fn set_in_buf(&self, addr0: usize, addr1: Option<usize>, max_packet_size: usize) {
+        self.in_buf.set(Some(BufDescriptor { addr0, addr1, size: max_packet_size }));
+
+        let btable_index = self.index as usize * 4;
+
+        pma_write16(btable_index, addr0 as u16);
+        pma_write16(btable_index + 1, 0);
+
+        if let Some(addr1) = addr1 {
+            // Double-buffered IN: slot 2/3, normally the OUT side, hold buffer 1.
+            pma_write16(btable_index + 2, addr1 as u16);
+            pma_write16(btable_index + 3, 0);
+        }
+    }
+
+    fn reg(&self) -> &EP0R {
+        unsafe { &*((0x4000_6000 + 0x400 + self.index as usize * 4) as *const EP0R) }
+    }
+
+    pub fn read_reg(&self) -> stm32f103xx::usb::ep0r::R {
+        self.reg().read()
+    }
+
+    /// Writes `toggle` bits (a subset of `STAT_TX | DTOG_TX | STAT_RX | DTOG_RX`) as 1 to flip
+    /// them, while re-asserting the invariant fields (`EA`/`EP_KIND`/`EP_TYPE`) and preserving
+    /// `CTR_TX`/`CTR_RX` unless `clear` says to clear one of them.
+    fn write_epr(&self, toggle: u16, clear: u16) {
+        let current = self.reg().read().bits();
+        let value = (current & EPR_INVARIANT) | (EPR_RC_W0 & !clear) | (toggle & !EPR_RC_W0);
+
+        self.reg().write(|w| unsafe { w.bits(value) });
+    }
+
+    pub fn configure(&self, cs: &CriticalSection) {
+        let _ = cs;
+
+        let ep_type_bits = match self.ep_type.get() {
+            Some(EndpointType::Control) => 0b01,
+            Some(EndpointType::Isochronous) => 0b10,
+            Some(EndpointType::Bulk) => 0b00,
+            Some(EndpointType::Interrupt) => 0b11,
+            None => return,
+        };
+
+        let ep_kind = if self.double_buffered.get() { EPR_EP_KIND } else { 0 };
+
+        self.reg().write(|w| unsafe {
+            w.bits(
+                self.index as u16
+                    | ep_kind
+                    | (ep_type_bits << 9)
+                    | ((EndpointStatus::Valid as u16) << 12)
+                    | ((EndpointStatus::Nak as u16) << 4)
+                    | EPR_RC_W0,
+            )
+        });
+    }
+
+    pub fn clear_ctr_tx(&self, cs: &CriticalSection) {
+        let _ = cs;
+        self.write_epr(0, EPR_CTR_TX);
+    }
+
+    pub fn clear_ctr_rx(&self, cs: &CriticalSection) {
+        let _ = cs;
+        self.write_epr(0, EPR_CTR_RX);
+    }
+
+    /// Used by `UsbBus::on_interrupt` to deassert `ISTR.CTR` for a completed single-buffered OUT
+    /// transaction without losing it: forces `STAT_RX` to `Nak` (hardware already does this on
+    /// completion; asserting it explicitly documents the invariant `read` below relies on) so the
+    /// peripheral can't overwrite the buffer, then clears `CTR_RX` and remembers that a packet is
+    /// waiting to be drained by a later call to `read` from thread mode.
+    pub fn mask_out(&self, cs: &CriticalSection) {
+        self.set_stat_rx(cs, EndpointStatus::Nak);
+        self.clear_ctr_rx(cs);
+        self.out_pending.set(true);
+    }
+
+    pub fn set_stat_tx(&self, cs: &CriticalSection, status: EndpointStatus) {
+        let _ = cs;
+        let current = ((self.reg().read().bits() & EPR_STAT_TX) >> 4) as u8;
+        let toggle = (current ^ status as u8) as u16;
+        self.write_epr(toggle << 4, 0);
+    }
+
+    pub fn set_stat_rx(&self, cs: &CriticalSection, status: EndpointStatus) {
+        let _ = cs;
+        let current = ((self.reg().read().bits() & EPR_STAT_RX) >> 12) as u8;
+        let toggle = (current ^ status as u8) as u16;
+        self.write_epr(toggle << 12, 0);
+    }
+
+    /// Toggles `DTOG_TX`/`DTOG_RX` to switch the software buffer index for a double-buffered
+    /// endpoint. For double-buffered OUT endpoints the peripheral mirrors the active buffer in
+    /// `DTOG_TX` (not `DTOG_RX`) and vice versa for IN endpoints, per the reference manual.
+    fn toggle_sw_buf(&self, is_out: bool) {
+        self.sw_buf.set(!self.sw_buf.get());
+
+        let bit = if is_out { EPR_DTOG_TX } else { EPR_DTOG_RX };
+        self.write_epr(bit, 0);
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize> {
+        let in_buf = self.in_buf.get();
+        let in_buf = in_buf.as_ref().ok_or(UsbError::InvalidEndpoint)?;
+
+        if buf.len() > in_buf.size {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let double_buffered = self.double_buffered.get();
+
+        if !double_buffered && self.read_reg().stat_tx().bits() == EndpointStatus::Valid as u8 {
+            return Err(UsbError::WouldBlock);
+        }
+
+        let addr = match (double_buffered, self.sw_buf.get(), in_buf.addr1) {
+            (true, false, _) => in_buf.addr0,
+            (true, true, Some(addr1)) => addr1,
+            _ => in_buf.addr0,
+        };
+
+        pma_write(addr, buf);
+
+        let btable_index = self.index as usize * 4;
+        let count_offset = if double_buffered && self.sw_buf.get() { btable_index + 3 } else { btable_index + 1 };
+        pma_write16(count_offset, buf.len() as u16);
+
+        cortex_m::interrupt::free(|cs| {
+            if double_buffered {
+                // CTR_TX is still set by hardware for each completed IN transaction in double
+                // buffer mode. It is cleared here before switching buffers, in addition to
+                // `poll`/`on_interrupt`, which clear it for every endpoint when they see it.
+                self.clear_ctr_tx(cs);
+                self.toggle_sw_buf(false);
+            } else {
+                self.set_stat_tx(cs, EndpointStatus::Valid);
+            }
+        });
+
+        Ok(buf.len())
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        let out_buf = self.out_buf.get();
+        let out_buf = out_buf.as_ref().ok_or(UsbError::InvalidEndpoint)?;
+
+        let double_buffered = self.double_buffered.get();
+
+        let reg = self.read_reg();
+        if !double_buffered && reg.ctr_rx().bit_is_clear() && !self.out_pending.get() {
+            return Err(UsbError::WouldBlock);
+        }
+
+        let btable_index = self.index as usize * 4;
+
+        let (addr, count_offset) = match (double_buffered, self.sw_buf.get(), out_buf.addr1) {
+            (true, false, _) => (out_buf.addr0, btable_index + 1),
+            (true, true, Some(addr1)) => (addr1, btable_index + 3),
+            _ => (out_buf.addr0, btable_index + 3),
+        };
+
+        let count = (pma_read16(count_offset) & 0x3ff) as usize;
+
+        if count > buf.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        pma_read(addr, &mut buf[0..count]);
+
+        cortex_m::interrupt::free(|cs| {
+            if double_buffered {
+                // CTR_RX is still raised per completed OUT transaction in double buffer mode and,
+                // unlike CTR_TX, `poll` never clears it, so it must be cleared here, or `poll`
+                // reports this endpoint's buffer as ready forever and ISTR.CTR never deasserts.
+                self.clear_ctr_rx(cs);
+                self.toggle_sw_buf(true);
+            } else {
+                self.clear_ctr_rx(cs);
+                self.set_stat_rx(cs, EndpointStatus::Valid);
+                self.out_pending.set(false);
+            }
+        });
+
+        Ok(count)
+    }
+}
@@ -0,0 +1,21 @@
+//! USB peripheral driver for STM32F103xx microcontrollers, implementing the `usb-device` traits.
+
+#![no_std]
+
+extern crate bare_metal;
+extern crate cortex_m;
+extern crate embedded_hal;
+extern crate stm32f103xx;
+extern crate stm32f103xx_hal;
+extern crate usb_device;
+
+mod bus;
+mod endpoint;
+mod peripheral;
+mod ring_buffer;
+pub mod asynch;
+pub mod buffered_serial;
+pub mod cdc_acm;
+
+pub use bus::UsbBus;
+pub use peripheral::UsbPeripheral;
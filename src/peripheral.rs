@@ -0,0 +1,23 @@
+use core::ops::Deref;
+use stm32f103xx::usb::RegisterBlock;
+use stm32f103xx_hal::rcc;
+
+/// A USB peripheral instance that [`UsbBus`](::UsbBus) can drive.
+///
+/// Implemented for [`stm32f103xx::USB`](::stm32f103xx::USB) below. Downstream forks targeting
+/// parts with a pin-remapped or otherwise compatible USB peripheral (but a different PAC type)
+/// can implement this for their own register struct instead of forking the driver.
+pub trait UsbPeripheral: Deref<Target = RegisterBlock> + 'static {
+    /// Enables the peripheral's clock in `RCC_APB1ENR`.
+    fn enable_clock(apb1: &mut rcc::APB1);
+}
+
+impl UsbPeripheral for ::stm32f103xx::USB {
+    fn enable_clock(apb1: &mut rcc::APB1) {
+        // TODO: apb1.enr is not public, figure out how this should really interact with the HAL
+        // crate
+        ::cortex_m::interrupt::free(|_| {
+            apb1.enr().modify(|_, w| w.usben().enabled());
+        });
+    }
+}
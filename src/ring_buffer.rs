@@ -0,0 +1,79 @@
+//! A small fixed-capacity byte ring buffer, used by [`buffered_serial`] to decouple USB packet
+//! boundaries from application reads/writes.
+
+const CAPACITY: usize = 256;
+
+pub struct RingBuffer {
+    buf: [u8; CAPACITY],
+    head: usize,
+    tail: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    pub const fn new() -> Self {
+        RingBuffer { buf: [0; CAPACITY], head: 0, tail: 0, len: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == CAPACITY
+    }
+
+    /// Number of bytes that can still be `push_slice`d before the buffer is full.
+    pub fn free(&self) -> usize {
+        CAPACITY - self.len
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.tail = 0;
+        self.len = 0;
+    }
+
+    /// Copies as much of `data` into the buffer as fits, returning the number of bytes copied.
+    pub fn push_slice(&mut self, data: &[u8]) -> usize {
+        let count = core::cmp::min(data.len(), CAPACITY - self.len);
+
+        for &byte in &data[0..count] {
+            self.buf[self.tail] = byte;
+            self.tail = (self.tail + 1) % CAPACITY;
+        }
+
+        self.len += count;
+
+        count
+    }
+
+    /// Copies as many buffered bytes into `data` as fit, returning the number of bytes copied.
+    pub fn pop_slice(&mut self, data: &mut [u8]) -> usize {
+        let count = self.peek_slice(data);
+        self.consume(count);
+        count
+    }
+
+    /// Copies as many buffered bytes into `data` as fit, without removing them. Pair with
+    /// `consume` once the copy is known to have been handed off successfully, so a failed
+    /// hand-off can leave the bytes in place instead of losing or reordering them.
+    pub fn peek_slice(&self, data: &mut [u8]) -> usize {
+        let count = core::cmp::min(data.len(), self.len);
+        let mut pos = self.head;
+
+        for slot in &mut data[0..count] {
+            *slot = self.buf[pos];
+            pos = (pos + 1) % CAPACITY;
+        }
+
+        count
+    }
+
+    /// Removes `count` bytes previously returned by `peek_slice` from the front of the buffer.
+    pub fn consume(&mut self, count: usize) {
+        let count = core::cmp::min(count, self.len);
+        self.head = (self.head + count) % CAPACITY;
+        self.len -= count;
+    }
+}